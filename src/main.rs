@@ -2,19 +2,25 @@
 extern crate serde_derive;
 use anyhow::{bail, Context, Result};
 use fxhash::FxBuildHasher;
-use md5::Digest;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
 use parking_lot::Mutex;
 use std::{
     cmp::Ordering,
     collections::{HashMap, HashSet},
+    io::ErrorKind,
     iter::FromIterator,
     mem,
+    os::unix::{fs::MetadataExt, io::AsRawFd},
     path::{Path, PathBuf},
     sync::Arc,
 };
 use structopt::StructOpt;
 use tokio::{
-    fs::{canonicalize, metadata, read_dir, read_link, remove_file, File},
+    fs::{canonicalize, metadata, read_dir, read_link, remove_file, rename, File, OpenOptions},
     io::AsyncReadExt,
     process::Command,
     sync::{OwnedSemaphorePermit, Semaphore},
@@ -23,6 +29,208 @@ use tokio::{
 
 const BUF: usize = 32384;
 
+// large enough that `update_rayon` has real work to fan out across cores,
+// small enough that hashing a multi-gigabyte file doesn't pull more than
+// this much of it into memory at once
+const BLAKE3_CHUNK: usize = 1 << 20;
+
+// not exposed by the libc crate; see linux ioctl_ficlone(2)
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+/// the `.gitignore` files in effect for a directory, from the root down
+/// to (and including) that directory itself, used when `--respect-gitignore`
+/// is set. child directories extend their parent's stack with their own
+/// `.gitignore`, if they have one, so rules loaded deeper in the tree can
+/// override (or, via `!`, un-ignore) rules loaded higher up
+#[derive(Clone, Default)]
+struct IgnoreStack(Vec<Arc<Gitignore>>);
+
+impl IgnoreStack {
+    fn descend(&self, dir: &Path) -> Result<IgnoreStack> {
+        let candidate = dir.join(".gitignore");
+        if !candidate.is_file() {
+            return Ok(self.clone());
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(e) = builder.add(&candidate) {
+            bail!("parsing {:?}: {}", candidate, e)
+        }
+        let mut stack = self.0.clone();
+        stack.push(Arc::new(builder.build().with_context(|| format!("compiling {:?}", candidate))?));
+        Ok(IgnoreStack(stack))
+    }
+
+    /// later (deeper) files take precedence over earlier ones, and a `!`
+    /// negation inside one file only overrides matches from files before
+    /// it in the stack, so the last non-`None` verdict wins
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for gi in &self.0 {
+            match gi.matched(path, is_dir) {
+                Match::None => (),
+                Match::Ignore(_) => ignored = true,
+                Match::Whitelist(_) => ignored = false,
+            }
+        }
+        ignored
+    }
+}
+
+/// compiled `--include`/`--exclude` patterns and whether `--respect-gitignore`
+/// was passed, threaded down through `scan_dir` to decide whether a path
+/// is skipped before it's ever opened or descended into
+struct Filters {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    respect_gitignore: bool,
+}
+
+/// patterns are matched against the full (absolute) `dirent.path()`, so a
+/// pattern with no `/` in it is rewritten to match at any depth — the way
+/// a slash-less `.gitignore` pattern does — rather than only matching a
+/// path that is itself exactly that one component long
+fn glob(pat: &str) -> Result<Glob> {
+    let anchored = if pat.contains('/') { pat.to_string() } else { format!("**/{}", pat) };
+    Glob::new(&anchored).with_context(|| format!("invalid glob {:?}", pat))
+}
+
+impl Filters {
+    fn new(cfg: &Opt) -> Result<Self> {
+        let mut exclude = GlobSetBuilder::new();
+        for pat in &cfg.exclude {
+            exclude.add(glob(pat)?);
+        }
+        let include = if cfg.include.is_empty() {
+            None
+        } else {
+            let mut include = GlobSetBuilder::new();
+            for pat in &cfg.include {
+                include.add(glob(pat)?);
+            }
+            Some(include.build().context("compiling --include globs")?)
+        };
+        Ok(Filters {
+            include,
+            exclude: exclude.build().context("compiling --exclude globs")?,
+            respect_gitignore: cfg.respect_gitignore,
+        })
+    }
+
+    /// true if `path` should never be descended into or hashed:
+    /// explicitly `--exclude`d, or ignored per `ignores` when
+    /// `--respect-gitignore` is set. applies to every path regardless of
+    /// its eventual type, so it's checked once up front, before the
+    /// symlink/dir/file dispatch decides what `path` actually is
+    fn skip_excluded(&self, path: &Path, is_dir: bool, ignores: &IgnoreStack) -> bool {
+        if self.exclude.is_match(path) {
+            return true;
+        }
+        self.respect_gitignore && ignores.is_ignored(path, is_dir)
+    }
+
+    /// true if `--include` was given and `path` doesn't match any of its
+    /// globs. only meaningful once a path has resolved to a regular
+    /// file: directories, and symlinks still awaiting resolution, are
+    /// exempt so descending into them can still reach an included file
+    /// further down
+    fn skip_not_included(&self, path: &Path) -> bool {
+        match &self.include {
+            Some(include) => !include.is_match(path),
+            None => false,
+        }
+    }
+}
+
+/// digest algorithms `scan_file`/`scan_file_partial` can produce, selected
+/// with `--hash`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HashAlgo {
+    Md5,
+    Blake3,
+}
+
+impl std::str::FromStr for HashAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "md5" => Ok(HashAlgo::Md5),
+            "blake3" => Ok(HashAlgo::Blake3),
+            s => Err(format!("unknown hash algorithm {:?}, expected md5 or blake3", s)),
+        }
+    }
+}
+
+/// a digest produced by one of the algorithms in `HashAlgo`. kept as an
+/// enum rather than a fixed-size array so `res` and `Duplicate` don't need
+/// to hardcode MD5's 16-byte width
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum FileDigest {
+    Md5([u8; 16]),
+    Blake3([u8; 32]),
+}
+
+impl FileDigest {
+    fn algo(&self) -> HashAlgo {
+        match self {
+            FileDigest::Md5(_) => HashAlgo::Md5,
+            FileDigest::Blake3(_) => HashAlgo::Blake3,
+        }
+    }
+}
+
+/// the digest produced by hashing only the first `BUF` bytes of a file,
+/// used to cheaply rule out candidates before paying for a full read
+type PartialDigest = FileDigest;
+
+/// identity and last-modified time of a file as reported by `stat`,
+/// used both to validate a `--cache` entry and to record a fresh one
+#[derive(Debug, Clone, Copy)]
+struct FileStat {
+    dev: u64,
+    ino: u64,
+    mtime: i64,
+    len: u64,
+}
+
+/// a single `--cache` record: the file's identity at the time it was
+/// hashed, and the digest that hashing produced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    dev: u64,
+    ino: u64,
+    mtime: i64,
+    len: u64,
+    digest: FileDigest,
+}
+
+impl CacheEntry {
+    fn matches(&self, stat: &FileStat) -> bool {
+        self.dev == stat.dev && self.ino == stat.ino && self.len == stat.len && self.mtime == stat.mtime
+    }
+}
+
+/// load a previously written `--cache` file, returning an empty map if
+/// it doesn't exist yet
+fn load_cache(path: &Path) -> Result<HashMap<PathBuf, CacheEntry>> {
+    match std::fs::read(path) {
+        Ok(data) => {
+            serde_json::from_slice(&data).with_context(|| format!("parsing cache {:?}", path))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e).with_context(|| format!("reading cache {:?}", path)),
+    }
+}
+
+/// write the `--cache` file atomically, via a temporary file in the same
+/// directory followed by a rename
+fn save_cache(path: &Path, cache: &HashMap<PathBuf, CacheEntry>) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let data = serde_json::to_vec(cache).context("serializing cache")?;
+    std::fs::write(&tmp, data).with_context(|| format!("writing cache {:?}", tmp))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("renaming cache {:?} into place", tmp))
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "fdedup")]
 struct Opt {
@@ -43,6 +251,34 @@ struct Opt {
     pretend: bool,
     #[structopt(long = "exec", help = "pass each duplicate set to program")]
     exec: Option<PathBuf>,
+    #[structopt(
+        long = "link",
+        help = "replace duplicates with links to one canonical copy instead of deleting them"
+    )]
+    link: bool,
+    #[structopt(
+        long = "cache",
+        help = "remember (dev, inode, mtime, size) -> digest between runs so unchanged files are never fully re-hashed"
+    )]
+    cache: Option<PathBuf>,
+    #[structopt(
+        long = "hash",
+        help = "digest algorithm to use: md5 or blake3",
+        default_value = "md5"
+    )]
+    hash: HashAlgo,
+    #[structopt(long = "exclude", help = "skip paths matching this glob, may be repeated")]
+    exclude: Vec<String>,
+    #[structopt(
+        long = "include",
+        help = "only consider files matching this glob, may be repeated"
+    )]
+    include: Vec<String>,
+    #[structopt(
+        long = "respect-gitignore",
+        help = "skip paths ignored by .gitignore files found while traversing"
+    )]
+    respect_gitignore: bool,
     #[structopt(name = "path")]
     path: PathBuf,
 }
@@ -52,32 +288,109 @@ impl Opt {
         if self.keep_shortest && self.exec.is_some() {
             bail!("can't specify both -exec and --keep-shortest")
         }
-        if self.pretend && !(self.keep_shortest || self.exec.is_some()) {
-            bail!("pretend only makes sense with --keep-shortest or --exec")
+        if self.link && (self.keep_shortest || self.exec.is_some()) {
+            bail!("can't specify --link with --keep-shortest or --exec")
+        }
+        if self.pretend && !(self.keep_shortest || self.exec.is_some() || self.link) {
+            bail!("pretend only makes sense with --keep-shortest, --exec, or --link")
         }
         Ok(())
     }
 }
 
-async fn scan_file<P: AsRef<Path>>(permit: OwnedSemaphorePermit, path: P) -> Result<Digest> {
-    let res = {
-        let mut ctx = md5::Context::new();
-        let mut fd = File::open(path.as_ref())
+async fn hash_md5(path: &Path) -> Result<FileDigest> {
+    let mut ctx = md5::Context::new();
+    let mut fd = File::open(path)
+        .await
+        .with_context(|| format!("error opening file {:?}", path))?;
+    let mut contents = [0u8; BUF];
+    loop {
+        let n = fd
+            .read(&mut contents[0..])
             .await
-            .with_context(|| format!("error opening file {:?}", path.as_ref()))?;
-        let mut contents = [0u8; BUF];
-        loop {
-            let n = fd
-                .read(&mut contents[0..])
-                .await
-                .with_context(|| format!("error reading file {:?}", path.as_ref()))?;
-            if n > 0 {
-                ctx.consume(&contents[0..n])
-            } else {
-                break;
-            }
+            .with_context(|| format!("error reading file {:?}", path))?;
+        if n > 0 {
+            ctx.consume(&contents[0..n])
+        } else {
+            break;
+        }
+    }
+    Ok(FileDigest::Md5(ctx.compute().0))
+}
+
+async fn hash_md5_partial(path: &Path) -> Result<FileDigest> {
+    let mut ctx = md5::Context::new();
+    let mut fd = File::open(path)
+        .await
+        .with_context(|| format!("error opening file {:?}", path))?;
+    let mut contents = [0u8; BUF];
+    let n = fd
+        .read(&mut contents[0..])
+        .await
+        .with_context(|| format!("error reading file {:?}", path))?;
+    ctx.consume(&contents[0..n]);
+    Ok(FileDigest::Md5(ctx.compute().0))
+}
+
+/// read a file in `BLAKE3_CHUNK`-sized pieces, feeding each one to
+/// `update_rayon` so its internal tree hash still fans out across cores
+/// the way a single whole-file `update_rayon` call would, but without
+/// pulling a multi-gigabyte file entirely into memory to do it
+async fn hash_blake3(path: &Path) -> Result<FileDigest> {
+    let mut hasher = blake3::Hasher::new();
+    let mut fd = File::open(path)
+        .await
+        .with_context(|| format!("error opening file {:?}", path))?;
+    let mut buf = vec![0u8; BLAKE3_CHUNK];
+    loop {
+        let n = fd
+            .read(&mut buf[0..])
+            .await
+            .with_context(|| format!("error reading file {:?}", path))?;
+        if n == 0 {
+            break;
         }
-        Ok(ctx.compute())
+        hasher.update_rayon(&buf[0..n]);
+    }
+    Ok(FileDigest::Blake3(*hasher.finalize().as_bytes()))
+}
+
+async fn hash_blake3_partial(path: &Path) -> Result<FileDigest> {
+    let mut fd = File::open(path)
+        .await
+        .with_context(|| format!("error opening file {:?}", path))?;
+    let mut contents = [0u8; BUF];
+    let n = fd
+        .read(&mut contents[0..])
+        .await
+        .with_context(|| format!("error reading file {:?}", path))?;
+    Ok(FileDigest::Blake3(*blake3::hash(&contents[0..n]).as_bytes()))
+}
+
+async fn scan_file<P: AsRef<Path>>(
+    permit: OwnedSemaphorePermit,
+    path: P,
+    algo: HashAlgo,
+) -> Result<FileDigest> {
+    let res = match algo {
+        HashAlgo::Md5 => hash_md5(path.as_ref()).await,
+        HashAlgo::Blake3 => hash_blake3(path.as_ref()).await,
+    };
+    drop(permit);
+    res
+}
+
+/// hash only the first `BUF` bytes of the file. for files no longer than
+/// `BUF` this is equivalent to `scan_file`, so callers skip this stage
+/// entirely in that case rather than hashing the same bytes twice
+async fn scan_file_partial<P: AsRef<Path>>(
+    permit: OwnedSemaphorePermit,
+    path: P,
+    algo: HashAlgo,
+) -> Result<PartialDigest> {
+    let res = match algo {
+        HashAlgo::Md5 => hash_md5_partial(path.as_ref()).await,
+        HashAlgo::Blake3 => hash_blake3_partial(path.as_ref()).await,
     };
     drop(permit);
     res
@@ -86,10 +399,11 @@ async fn scan_file<P: AsRef<Path>>(permit: OwnedSemaphorePermit, path: P) -> Res
 async fn scan_dir<P: AsRef<Path>>(
     cfg: Arc<Opt>,
     tasks: Arc<Mutex<Vec<JoinHandle<Result<()>>>>>,
-    dirs: Arc<Mutex<Vec<PathBuf>>>,
-    res: Arc<Mutex<HashMap<Digest, HashSet<PathBuf>, FxBuildHasher>>>,
+    dirs: Arc<Mutex<Vec<(PathBuf, IgnoreStack)>>>,
+    inodes: Arc<Mutex<HashMap<(u64, u64), (FileStat, Vec<PathBuf>)>>>,
     dir_sem: Arc<Semaphore>,
-    file_sem: Arc<Semaphore>,
+    filters: Arc<Filters>,
+    ignores: IgnoreStack,
     path: P,
 ) -> Result<()> {
     let permit = dir_sem.acquire_owned().await?;
@@ -107,6 +421,9 @@ async fn scan_dir<P: AsRef<Path>>(
             .metadata()
             .await
             .with_context(|| format!("getting metadata for {:?}", path))?;
+        if filters.skip_excluded(&path, md.is_dir(), &ignores) {
+            continue;
+        }
         loop {
             let ft = md.file_type();
             if ft.is_symlink() {
@@ -141,23 +458,35 @@ async fn scan_dir<P: AsRef<Path>>(
                 let path = canonicalize(&path)
                     .await
                     .with_context(|| format!("getting canonical path of dir {:?}", path))?;
-                dirs.lock().push(path);
+                let ignores = if filters.respect_gitignore {
+                    ignores.descend(&path)?
+                } else {
+                    ignores.clone()
+                };
+                dirs.lock().push((path, ignores));
                 break;
             } else if md.len() == 0 {
                 eprintln!("skipping empty file {:?}", path);
                 break;
             } else if ft.is_file() {
-                let res = res.clone();
-                let permit = file_sem.clone().acquire_owned().await?;
-                let task = task::spawn(async move {
-                    let digest = scan_file(permit, &path).await?;
-                    res.lock()
-                        .entry(digest)
-                        .or_insert_with(HashSet::new)
-                        .insert(path);
-                    Ok(())
-                });
-                tasks.lock().push(task);
+                if filters.skip_not_included(&path) {
+                    break;
+                }
+                let stat = FileStat {
+                    dev: md.dev(),
+                    ino: md.ino(),
+                    mtime: md.mtime(),
+                    len: md.len(),
+                };
+                // multiple paths can be hardlinks of the same inode; collapse
+                // them here so the rest of the pipeline hashes each inode
+                // exactly once no matter how many names it has
+                inodes
+                    .lock()
+                    .entry((stat.dev, stat.ino))
+                    .or_insert_with(|| (stat, Vec::new()))
+                    .1
+                    .push(path);
                 break;
             } else {
                 eprintln!("skipping non regular file {:?}", path);
@@ -171,38 +500,185 @@ async fn scan_dir<P: AsRef<Path>>(
 
 #[derive(Debug, Serialize)]
 struct Duplicate {
-    digest: [u8; 16],
-    paths: HashSet<PathBuf>,
+    digest: FileDigest,
+    // one entry per inode sharing this digest; an entry with more than
+    // one path is a set of hardlinks to a single already-deduplicated file
+    paths: Vec<HashSet<PathBuf>>,
+}
+
+/// the shortest (and, on a length tie, lexically first) path in a set of
+/// hardlinked paths, used both to order duplicate inodes and to pick the
+/// hardlink name `--link` clones from
+fn shortest(paths: &HashSet<PathBuf>) -> PathBuf {
+    paths
+        .iter()
+        .min_by(|p0, p1| match p0.to_string_lossy().len().cmp(&p1.to_string_lossy().len()) {
+            Ordering::Equal => p0.cmp(p1),
+            o => o,
+        })
+        .expect("a duplicate set is never empty")
+        .clone()
+}
+
+/// hash every path in `paths` with `scan_file_partial`, bounded by
+/// `file_sem`, returning the paths that hashed successfully paired with
+/// their partial digest
+async fn hash_partial_many(
+    file_sem: &Arc<Semaphore>,
+    paths: Vec<PathBuf>,
+    algo: HashAlgo,
+) -> Vec<(PathBuf, PartialDigest)> {
+    let mut tasks = Vec::with_capacity(paths.len());
+    for path in paths {
+        let file_sem = file_sem.clone();
+        tasks.push(task::spawn(async move {
+            let permit = file_sem.acquire_owned().await?;
+            let digest = scan_file_partial(permit, &path, algo).await?;
+            Ok::<_, anyhow::Error>((path, digest))
+        }));
+    }
+    let mut out = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        match task.await {
+            Err(e) => eprintln!("internal error awaiting task {}", e),
+            Ok(Err(e)) => eprintln!("WARNING! {}", e),
+            Ok(Ok(pair)) => out.push(pair),
+        }
+    }
+    out
+}
+
+/// hash every path in `paths` with `scan_file`, bounded by `file_sem`,
+/// returning the paths that hashed successfully paired with their digest
+async fn hash_full_many(
+    file_sem: &Arc<Semaphore>,
+    paths: Vec<PathBuf>,
+    algo: HashAlgo,
+) -> Vec<(PathBuf, FileDigest)> {
+    let mut tasks = Vec::with_capacity(paths.len());
+    for path in paths {
+        let file_sem = file_sem.clone();
+        tasks.push(task::spawn(async move {
+            let permit = file_sem.acquire_owned().await?;
+            let digest = scan_file(permit, &path, algo).await?;
+            Ok::<_, anyhow::Error>((path, digest))
+        }));
+    }
+    let mut out = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        match task.await {
+            Err(e) => eprintln!("internal error awaiting task {}", e),
+            Ok(Err(e)) => eprintln!("WARNING! {}", e),
+            Ok(Ok(pair)) => out.push(pair),
+        }
+    }
+    out
+}
+
+/// try to create `dst` as a copy-on-write clone of `src` (same data,
+/// independent inode), falling back to a plain hardlink when the
+/// filesystem or platform doesn't support reflinking
+async fn link_or_reflink(src: &Path, dst: &Path) -> Result<()> {
+    let src_fd = File::open(src)
+        .await
+        .with_context(|| format!("opening {:?} to link", src))?;
+    let dst_fd = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)
+        .await
+        .with_context(|| format!("creating {:?} to link", dst))?;
+    let rc = unsafe { libc::ioctl(dst_fd.as_raw_fd(), FICLONE, src_fd.as_raw_fd()) };
+    if rc == 0 {
+        return Ok(());
+    }
+    drop(dst_fd);
+    remove_file(dst)
+        .await
+        .with_context(|| format!("removing failed reflink target {:?}", dst))?;
+    std::fs::hard_link(src, dst).with_context(|| format!("hard linking {:?} to {:?}", src, dst))
+}
+
+/// replace `victim` with a link to `canonical`: the link is built under a
+/// temporary name in the same directory (guaranteeing the same filesystem)
+/// and then `rename`d over `victim` atomically. if that rename fails and
+/// `victim` is still the same length as `canonical`, `victim` is removed
+/// and linked directly instead, which leaves its path briefly missing
+async fn link_over(canonical: &Path, victim: &Path) -> Result<()> {
+    let tmp_name = match victim.file_name() {
+        Some(name) => format!(".fdedup-{}", name.to_string_lossy()),
+        None => bail!("{:?} has no file name", victim),
+    };
+    let tmp = victim.with_file_name(tmp_name);
+    link_or_reflink(canonical, &tmp).await?;
+    match rename(&tmp, victim).await {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = remove_file(&tmp).await;
+            let still_matches = metadata(victim).await?.len() == metadata(canonical).await?.len();
+            if !still_matches {
+                bail!(
+                    "renaming temporary link over {:?} failed and it no longer matches {:?}: {}",
+                    victim,
+                    canonical,
+                    e
+                )
+            }
+            remove_file(victim)
+                .await
+                .with_context(|| format!("removing {:?} before linking", victim))?;
+            link_or_reflink(canonical, victim).await
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cfg = Arc::new(Opt::from_args());
+    let mut cfg = Opt::from_args();
     cfg.validate()?;
+    // every path queued for scanning is canonicalized before it's reused
+    // as a directory to read (see the `ft.is_dir()` branch of `scan_dir`);
+    // canonicalizing the root here too keeps it consistent with them, so
+    // `--respect-gitignore`'s per-directory matchers and the paths they're
+    // matched against always agree on absolute vs. relative
+    cfg.path = canonicalize(&cfg.path)
+        .await
+        .with_context(|| format!("getting canonical path of {:?}", cfg.path))?;
+    let cfg = Arc::new(cfg);
+    let filters = Arc::new(Filters::new(&cfg)?);
+    let root_ignores = if filters.respect_gitignore {
+        IgnoreStack::default().descend(&cfg.path)?
+    } else {
+        IgnoreStack::default()
+    };
     let mut checked = HashSet::new();
     let dir_sem = Arc::new(Semaphore::new(256));
     let file_sem = Arc::new(Semaphore::new(512));
     let tasks = Arc::new(Mutex::new(vec![]));
-    let dirs = Arc::new(Mutex::new(vec![cfg.path.clone()]));
-    let res = Arc::new(Mutex::new(HashMap::with_hasher(FxBuildHasher::default())));
+    let dirs = Arc::new(Mutex::new(vec![(cfg.path.clone(), root_ignores)]));
+    let inodes = Arc::new(Mutex::new(HashMap::new()));
+    let (cache, cache_written_at) = match &cfg.cache {
+        Some(path) => (load_cache(path)?, std::fs::metadata(path).ok().map(|md| md.mtime())),
+        None => (HashMap::new(), None),
+    };
     let mut work = true;
     while work {
         let dirs_ = mem::replace(&mut *dirs.lock(), Vec::new());
         let tasks_ = mem::replace(&mut *tasks.lock(), Vec::new());
         work = dirs_.len() > 0 || tasks_.len() > 0;
-        for dir in dirs_ {
+        for (dir, ignores) in dirs_ {
             if checked.contains(&dir) {
                 eprintln!("skipping already checked directory {:?}", dir)
             } else {
                 checked.insert(dir.clone());
                 let tasks_ = tasks.clone();
                 let dirs = dirs.clone();
-                let res = res.clone();
-                let file_sem = file_sem.clone();
+                let inodes = inodes.clone();
                 let dir_sem = dir_sem.clone();
                 let cfg = cfg.clone();
+                let filters = filters.clone();
                 tasks.lock().push(task::spawn(async move {
-                    Ok(scan_dir(cfg, tasks_, dirs, res, dir_sem, file_sem, &dir)
+                    Ok(scan_dir(cfg, tasks_, dirs, inodes, dir_sem, filters, ignores, &dir)
                         .await
                         .with_context(|| format!("scanning directory {:?}", dir))?)
                 }));
@@ -216,32 +692,198 @@ async fn main() -> Result<()> {
             }
         }
     }
-    for (digest, paths) in res.lock().drain() {
-        if paths.len() > 1 {
+
+    // every path sharing an inode is one logical file; pick one of its
+    // names to carry through hashing and remember the rest so they can be
+    // reattached to the result below
+    let mut groups: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    let mut file_stats: HashMap<PathBuf, FileStat> = HashMap::new();
+    // cached members still occupy a slot in their size class (carrying the
+    // digest the cache already gave us for free) so a size class isn't
+    // mistaken for a singleton just because one of its members didn't need
+    // re-hashing; only a class with a single member, cached or not, can
+    // truly never have a duplicate
+    let mut sizes: HashMap<u64, Vec<(PathBuf, Option<FileDigest>)>, FxBuildHasher> =
+        HashMap::with_hasher(FxBuildHasher::default());
+    for (_, (stat, mut paths)) in inodes.lock().drain() {
+        paths.sort_unstable();
+        let representative = paths[0].clone();
+        // a file modified in the same clock tick the cache was written in
+        // might not be reflected in its mtime, so treat it as dirty rather
+        // than risk reusing a stale digest
+        let fresh = cache_written_at.map_or(true, |t| stat.mtime < t);
+        let cached_digest = if fresh {
+            paths
+                .iter()
+                .find_map(|p| cache.get(p))
+                .filter(|e| e.matches(&stat) && e.digest.algo() == cfg.hash)
+                .map(|e| e.digest)
+        } else {
+            None
+        };
+        file_stats.insert(representative.clone(), stat);
+        groups.insert(representative.clone(), paths);
+        sizes
+            .entry(stat.len)
+            .or_insert_with(Vec::new)
+            .push((representative, cached_digest));
+    }
+
+    let mut new_cache: HashMap<PathBuf, CacheEntry> = HashMap::new();
+    let mut res: HashMap<FileDigest, Vec<HashSet<PathBuf>>, FxBuildHasher> =
+        HashMap::with_hasher(FxBuildHasher::default());
+    let mut record = |representative: PathBuf, digest: FileDigest| {
+        let stat = file_stats.get(&representative).copied();
+        let members = groups.remove(&representative).unwrap_or_else(|| vec![representative]);
+        if cfg.cache.is_some() {
+            if let Some(stat) = stat {
+                for member in &members {
+                    new_cache.insert(
+                        member.clone(),
+                        CacheEntry {
+                            dev: stat.dev,
+                            ino: stat.ino,
+                            mtime: stat.mtime,
+                            len: stat.len,
+                            digest,
+                        },
+                    );
+                }
+            }
+        }
+        res.entry(digest)
+            .or_insert_with(Vec::new)
+            .push(HashSet::from_iter(members));
+    };
+    // stage 1: within each size class, a lone file can never have a
+    // duplicate, so drop it without ever opening it
+    let size_classes: Vec<(u64, Vec<(PathBuf, Option<FileDigest>)>)> =
+        sizes.into_iter().filter(|(_, members)| members.len() > 1).collect();
+
+    for (len, members) in size_classes {
+        // cached members already carry the digest the cache gave us for
+        // free; record them straight away instead of re-reading a file
+        // whose contents we already verified are unchanged. whatever's
+        // left still needs hashing, if only to check it against them
+        let mut cached_paths = Vec::new();
+        let mut paths = Vec::new();
+        for (path, digest) in members {
+            match digest {
+                Some(digest) => {
+                    record(path.clone(), digest);
+                    cached_paths.push(path);
+                }
+                None => paths.push(path),
+            }
+        }
+        if paths.is_empty() {
+            continue;
+        }
+        if (len as usize) <= BUF {
+            // the partial hash would cover the whole file anyway, so go
+            // straight to the full digest
+            for (path, digest) in hash_full_many(&file_sem, paths, cfg.hash).await {
+                record(path, digest);
+            }
+        } else {
+            // stage 2: cheaply rule out files that differ in their first
+            // block before paying for a full read. a cached member's
+            // full digest doesn't tell us its first block, so it's
+            // partial-hashed here too and seeded into the same classes;
+            // otherwise a new file that collides with it would be
+            // dropped for being the only *uncached* member of its class
+            let mut partial_classes: HashMap<PartialDigest, (usize, Vec<PathBuf>), FxBuildHasher> =
+                HashMap::with_hasher(FxBuildHasher::default());
+            for (_, digest) in hash_partial_many(&file_sem, cached_paths, cfg.hash).await {
+                partial_classes.entry(digest).or_insert_with(|| (0, Vec::new())).0 += 1;
+            }
+            for (path, digest) in hash_partial_many(&file_sem, paths, cfg.hash).await {
+                partial_classes
+                    .entry(digest)
+                    .or_insert_with(|| (0, Vec::new()))
+                    .1
+                    .push(path);
+            }
+            // stage 3: only files that collide on both size and partial
+            // hash are worth a full read, whether that collision is
+            // against another fresh file or an already-recorded cached one
+            for (_, (cached_count, paths)) in partial_classes {
+                if !paths.is_empty() && cached_count + paths.len() > 1 {
+                    for (path, digest) in hash_full_many(&file_sem, paths, cfg.hash).await {
+                        record(path, digest);
+                    }
+                }
+            }
+        }
+    }
+    drop(record);
+
+    if let Some(path) = &cfg.cache {
+        save_cache(path, &new_cache)?;
+    }
+
+    for (digest, mut groups) in res.drain() {
+        // a single inode, however many hardlink names it has, is not a
+        // duplicate; it takes at least two distinct inodes to reclaim space
+        if groups.len() > 1 {
             if cfg.keep_shortest {
-                let mut v = Vec::from_iter(paths);
-                v.sort_unstable_by(|v0, v1| {
-                    match v0.to_string_lossy().len().cmp(&v1.to_string_lossy().len()) {
-                        Ordering::Equal => v0.cmp(v1),
-                        v => v,
+                groups.sort_unstable_by(|g0, g1| {
+                    let s0 = shortest(g0);
+                    let s1 = shortest(g1);
+                    match s0.to_string_lossy().len().cmp(&s1.to_string_lossy().len()) {
+                        Ordering::Equal => s0.cmp(&s1),
+                        o => o,
                     }
                 });
                 if !cfg.pretend {
-                    for file in v.into_iter().skip(1) {
-                        remove_file(file).await?
+                    for group in groups.into_iter().skip(1) {
+                        for file in group {
+                            remove_file(file).await?
+                        }
                     }
                 } else {
                     let mut first = true;
-                    for file in v.into_iter() {
-                        if first {
-                            first = false;
-                            println!("would keep   : {:?}", file)
-                        } else {
-                            println!("would delete : {:?}", file)
+                    for group in groups {
+                        for file in group {
+                            if first {
+                                println!("would keep   : {:?}", file)
+                            } else {
+                                println!("would delete : {:?}", file)
+                            }
+                        }
+                        first = false;
+                    }
+                }
+            } else if cfg.link {
+                groups.sort_unstable_by(|g0, g1| {
+                    let s0 = shortest(g0);
+                    let s1 = shortest(g1);
+                    match s0.to_string_lossy().len().cmp(&s1.to_string_lossy().len()) {
+                        Ordering::Equal => s0.cmp(&s1),
+                        o => o,
+                    }
+                });
+                let mut groups = groups.into_iter();
+                let canonical_group = groups.next().expect("checked len() > 1 above");
+                let canonical = shortest(&canonical_group);
+                if cfg.pretend {
+                    println!("would keep   : {:?}", canonical_group);
+                    for victim_group in groups {
+                        for victim in victim_group {
+                            println!("would link   : {:?} -> {:?}", victim, canonical);
+                        }
+                    }
+                } else {
+                    for victim_group in groups {
+                        for victim in victim_group {
+                            if let Err(e) = link_over(&canonical, &victim).await {
+                                eprintln!("WARNING! failed to link {:?}, {}", victim, e);
+                            }
                         }
                     }
                 }
             } else if let Some(program) = &cfg.exec {
+                let paths: Vec<PathBuf> = groups.into_iter().flatten().collect();
                 if cfg.pretend {
                     println!("would run: {:?} {:?}", program, paths);
                 } else {
@@ -255,10 +897,7 @@ async fn main() -> Result<()> {
             } else {
                 println!(
                     "{}",
-                    serde_json::to_string(&Duplicate {
-                        digest: digest.0,
-                        paths: paths
-                    })?
+                    serde_json::to_string(&Duplicate { digest, paths: groups })?
                 );
             }
         }